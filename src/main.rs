@@ -1,18 +1,67 @@
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Json, Router};
-use clap::Parser;
+use axum::{
+    extract::FromRef, http::StatusCode, middleware, response::IntoResponse, routing::get, Json,
+    Router,
+};
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tracing::{debug, info};
 
-use sqlx::{sqlite::SqlitePoolOptions, Sqlite};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    Sqlite,
+};
 
 #[derive(Debug)]
 struct NonClonableStruct;
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(short, long, env = "DATABASE_URL", default_value = "sqlite:data.db")]
+    /// shared by every subcommand
+    #[arg(
+        short,
+        long,
+        env = "DATABASE_URL",
+        default_value = "sqlite:data.db",
+        global = true
+    )]
     db_url: String,
+
+    /// size of the sqlite connection pool
+    #[arg(long, env = "MAX_CONNECTIONS", default_value_t = 5, global = true)]
+    max_connections: u32,
+
+    /// how long to wait for a connection to become available before giving up
+    #[arg(long, env = "ACQUIRE_TIMEOUT_SECS", default_value_t = 30, global = true)]
+    acquire_timeout_secs: u64,
+
+    /// how long a connection can sit idle in the pool before being closed; unset means never
+    #[arg(long, env = "IDLE_TIMEOUT_SECS", global = true)]
+    idle_timeout_secs: Option<u64>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start the HTTP server
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:3000")]
+        addr: SocketAddr,
+
+        /// Requires this key on `/hit/:url` via `Authorization: Bearer <key>`
+        /// or `x-api-key`. Leave unset to keep the route open.
+        #[arg(long, env = "API_KEY")]
+        api_key: Option<String>,
+    },
+    /// Run pending migrations against the database, then exit
+    Migrate,
+    /// Print hit counts to stdout without starting the server
+    Stats {
+        /// print the count for just this target; omit to print every target
+        target: Option<String>,
+    },
 }
 
 // global program state will be managed by axum
@@ -24,6 +73,16 @@ pub struct ProgramState {
     some_other_data: String,
     // `NonCloneableStruct` does _not_ implement `Clone`, so we need to wrap it in an `Arc`:
     more_state: Arc<NonClonableStruct>,
+    // key required by `auth::RequireApiKey`; `None` leaves the protected routes open
+    api_key: Option<String>,
+}
+
+// lets extractors such as `tx::Tx` pull just the connection pool out of the
+// full program state instead of requiring `State<ProgramState>`
+impl FromRef<ProgramState> for sqlx::Pool<Sqlite> {
+    fn from_ref(state: &ProgramState) -> Self {
+        state.db_pool.clone()
+    }
 }
 
 #[tokio::main]
@@ -35,19 +94,91 @@ async fn main() {
     // get CLI args
     let args = Args::parse();
 
-    // connect to database
+    // connect to database, creating the database file if it doesn't already exist
+    let db_pool = connect(
+        &args.db_url,
+        args.max_connections,
+        args.acquire_timeout_secs,
+        args.idle_timeout_secs,
+    )
+    .await;
+
+    match args.command {
+        Command::Serve { addr, api_key } => {
+            // zero manual setup: bring the database up to date before serving
+            run_migrations(&db_pool).await;
+            serve(db_pool, addr, api_key).await;
+        }
+        Command::Migrate => run_migrations(&db_pool).await,
+        Command::Stats { target } => print_stats(&db_pool, target).await,
+    }
+}
+
+async fn connect(
+    db_url: &str,
+    max_connections: u32,
+    acquire_timeout_secs: u64,
+    idle_timeout_secs: Option<u64>,
+) -> sqlx::Pool<Sqlite> {
+    let connect_options: SqliteConnectOptions = db_url
+        .parse::<SqliteConnectOptions>()
+        .expect("failed to parse db url")
+        .create_if_missing(true)
+        .foreign_keys(true);
+
+    info!(
+        max_connections,
+        acquire_timeout_secs, ?idle_timeout_secs, "configuring connection pool"
+    );
     let db_pool = SqlitePoolOptions::new()
-        .connect(&args.db_url)
+        .max_connections(max_connections)
+        .acquire_timeout(Duration::from_secs(acquire_timeout_secs))
+        .idle_timeout(idle_timeout_secs.map(Duration::from_secs))
+        .connect_with(connect_options)
         .await
         .expect("failed to connect to database");
-    info!(db_url = args.db_url, "connected to database");
+    info!(db_url, "connected to database");
+    db_pool
+}
+
+async fn run_migrations(db_pool: &sqlx::Pool<Sqlite>) {
+    sqlx::migrate!("./migrations")
+        .run(db_pool)
+        .await
+        .expect("failed to run migrations");
+    info!("migrations applied");
+}
+
+async fn print_stats(db_pool: &sqlx::Pool<Sqlite>, target: Option<String>) {
+    match target {
+        Some(target) => {
+            let stat = query::get_stat(db_pool, &target)
+                .await
+                .expect("failed to query stats");
+            match stat {
+                Some(stat) => println!("{}: {}", stat.target, stat.count),
+                None => println!("{target}: 0"),
+            }
+        }
+        None => {
+            let stats = query::get_stats(db_pool, None)
+                .await
+                .expect("failed to query stats");
+            for stat in stats {
+                println!("{}: {}", stat.target, stat.count);
+            }
+        }
+    }
+}
 
+async fn serve(db_pool: sqlx::Pool<Sqlite>, addr: SocketAddr, api_key: Option<String>) {
     // create new program state
     let state = ProgramState {
         db_pool,
         some_other_data: String::from("hello"),
         // manually wrap this in Arc because it didn't derive Clone
         more_state: Arc::new(NonClonableStruct),
+        api_key,
     };
     info!("state created");
 
@@ -58,14 +189,18 @@ async fn main() {
         .route("/hit", get(route::root))
         // `GET /hit/<anything>`
         .route("/hit/:url", get(route::hit))
+        // `GET /stats`, optionally `?limit=`
+        .route("/stats", get(route::stats))
+        // give every request a transaction slot that `tx::Tx` can lazily open
+        // and that gets committed/rolled back once the response is ready
+        .layer(middleware::from_fn(tx::middleware))
         // move the program state into the axum app
         .with_state(state);
     info!("app built");
 
     // run our app with hyper
     // `axum::Server` is a re-export of `hyper::Server`
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    info!("listening on {}", addr);
+    info!(%addr, "listening");
     axum::Server::bind(&addr)
         // set up this server with our app from above
         .serve(app.into_make_service())
@@ -75,9 +210,9 @@ async fn main() {
 
 // separate routes into own module
 mod route {
-    use axum::extract::{Path, State};
+    use axum::extract::{Path, Query, State};
 
-    use super::*;
+    use super::{auth, error, tx::Tx, *};
 
     pub async fn root() -> impl IntoResponse {
         "Navigate to `/hit/foo` to increment the hit count for `foo`"
@@ -101,17 +236,30 @@ mod route {
     //    so the State still exists within Axum because we used .with_state() when
     //    we created the app.
     pub async fn hit(
-        Path(url): Path<String>,           // access to the URL
-        State(state): State<ProgramState>, // access to entire program state
-    ) -> impl IntoResponse {
+        Path(url): Path<String>,   // access to the URL
+        _key: auth::RequireApiKey, // rejects the request if an API key is configured and missing/wrong
+        mut tx: Tx,                // lazily-opened, per-request transaction
+    ) -> error::Result<impl IntoResponse> {
         info!(url = url, "hit");
-        // get the db pool from the program state, and then get a connection from it
-        let connection = state.db_pool.acquire().await.unwrap();
-        // pass connection to increase_hit_count so we can update the hits
-        let hits = query::increase_hit_count(&url, connection).await.unwrap();
+        // pass the transaction to increase_hit_count so we can update the hits
+        let hits = query::increase_hit_count(&url, &mut tx).await?;
         info!(url = url, hits = hits, "total hits");
         // return the total hits
-        format!("{hits}")
+        Ok(format!("{hits}"))
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct StatsParams {
+        // caps the number of rows returned; unset means "all of them"
+        limit: Option<i64>,
+    }
+
+    pub async fn stats(
+        State(state): State<ProgramState>,
+        Query(params): Query<StatsParams>,
+    ) -> error::Result<Json<Vec<query::HitStat>>> {
+        let stats = query::get_stats(&state.db_pool, params.limit).await?;
+        Ok(Json(stats))
     }
 }
 
@@ -122,13 +270,39 @@ async fn root() -> &'static str {
 
 // separate the queries into own module
 mod query {
-    use sqlx::{pool::PoolConnection, Sqlite};
+    use serde::Serialize;
+    use sqlx::Sqlite;
     use tracing::info;
 
-    pub async fn increase_hit_count(
-        target: &str,
-        mut connection: PoolConnection<Sqlite>,
-    ) -> Result<i64, color_eyre::eyre::Report> {
+    use super::{error::Result, tx::Tx};
+
+    #[derive(Debug, Serialize)]
+    pub struct HitStat {
+        pub target: String,
+        pub count: i64,
+    }
+
+    pub async fn get_stats(pool: &sqlx::Pool<Sqlite>, limit: Option<i64>) -> Result<Vec<HitStat>> {
+        // SQLite treats a negative LIMIT as "no limit", so `None` needs no special case
+        let limit = limit.unwrap_or(-1);
+        Ok(sqlx::query_as!(
+            HitStat,
+            "SELECT target, count FROM hits ORDER BY count DESC LIMIT ?",
+            limit
+        )
+        .fetch_all(pool)
+        .await?)
+    }
+
+    pub async fn get_stat(pool: &sqlx::Pool<Sqlite>, target: &str) -> Result<Option<HitStat>> {
+        Ok(
+            sqlx::query_as!(HitStat, "SELECT target, count FROM hits WHERE target = ?", target)
+                .fetch_optional(pool)
+                .await?,
+        )
+    }
+
+    pub async fn increase_hit_count(target: &str, tx: &mut Tx) -> Result<i64> {
         // upsert hits
         sqlx::query!(
             "INSERT INTO hits(target, count) VALUES(?, 1)
@@ -136,7 +310,7 @@ mod query {
          count=count + 1",
             target
         )
-        .execute(&mut connection)
+        .execute(&mut **tx)
         .await?;
 
         info!(url = target, "hit count incremented");
@@ -144,9 +318,238 @@ mod query {
         // query the current count
         Ok(
             sqlx::query!("SELECT count FROM hits WHERE target = ?", target)
-                .fetch_one(&mut connection)
+                .fetch_one(&mut **tx)
                 .await?
                 .count,
         )
     }
 }
+
+// the `Tx` extractor: a per-request `sqlx::Transaction` that is opened lazily
+// and finalized by the `tx::middleware` layer once the response is ready
+mod tx {
+    use std::{
+        ops::{Deref, DerefMut},
+        sync::Arc,
+    };
+
+    use axum::{
+        async_trait,
+        extract::{FromRef, FromRequestParts},
+        http::{request::Parts, Request, StatusCode},
+        middleware::Next,
+        response::Response,
+    };
+    use sqlx::{Sqlite, Transaction};
+    use tokio::sync::Mutex;
+    use tracing::error;
+
+    // handed to every request via an extension so `middleware` and `Tx` can
+    // hand a transaction back and forth across the handler boundary
+    #[derive(Clone)]
+    struct TxSlot(Arc<Mutex<Option<Transaction<'static, Sqlite>>>>);
+
+    /// Per-request transaction. Lazily begins the transaction the first time a
+    /// handler extracts it, and derefs to the underlying `sqlx::Transaction` so
+    /// it can be passed straight to `sqlx::query!` calls.
+    ///
+    /// Requires [`middleware`] to be installed as a layer, which commits the
+    /// transaction when the handler's response is a success and rolls it back
+    /// otherwise.
+    pub struct Tx {
+        tx: Option<Transaction<'static, Sqlite>>,
+        slot: Arc<Mutex<Option<Transaction<'static, Sqlite>>>>,
+    }
+
+    #[async_trait]
+    impl<S> FromRequestParts<S> for Tx
+    where
+        S: Send + Sync,
+        sqlx::Pool<Sqlite>: FromRef<S>,
+    {
+        type Rejection = (StatusCode, &'static str);
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            let TxSlot(slot) = parts.extensions.get::<TxSlot>().cloned().ok_or((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Tx extractor used without the tx::middleware layer installed",
+            ))?;
+
+            // reuse the transaction already sitting in the slot (a previous
+            // extraction in this request), or begin a new one on first use
+            let existing = slot.lock().await.take();
+            let tx = match existing {
+                Some(tx) => tx,
+                None => sqlx::Pool::<Sqlite>::from_ref(state)
+                    .begin()
+                    .await
+                    .map_err(|error| {
+                        error!(%error, "failed to begin transaction");
+                        (StatusCode::INTERNAL_SERVER_ERROR, "failed to begin transaction")
+                    })?,
+            };
+
+            Ok(Tx {
+                tx: Some(tx),
+                slot,
+            })
+        }
+    }
+
+    // derefs straight to the connection (not the `Transaction` itself) because
+    // `Transaction` only implements `Executor` one level further in, via its
+    // own `Deref`/`DerefMut` to `DB::Connection` -- this lets `query::*`
+    // functions pass `&mut **tx` straight to `sqlx::query!` calls
+    impl Deref for Tx {
+        type Target = <Sqlite as sqlx::Database>::Connection;
+
+        fn deref(&self) -> &Self::Target {
+            self.tx.as_deref().expect("transaction taken before drop")
+        }
+    }
+
+    impl DerefMut for Tx {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            self.tx
+                .as_deref_mut()
+                .expect("transaction taken before drop")
+        }
+    }
+
+    impl Drop for Tx {
+        fn drop(&mut self) {
+            // hand the transaction back to the slot so `middleware` can
+            // commit or roll it back once the response is ready
+            if let Some(tx) = self.tx.take() {
+                if let Ok(mut guard) = self.slot.try_lock() {
+                    *guard = Some(tx);
+                }
+            }
+        }
+    }
+
+    /// Installs the per-request transaction slot and finalizes whatever
+    /// transaction ends up in it: committed on a 2xx response, rolled back
+    /// otherwise. No-ops if no handler ever extracted a [`Tx`].
+    pub async fn middleware<B>(mut req: Request<B>, next: Next<B>) -> Response {
+        let slot = Arc::new(Mutex::new(None));
+        req.extensions_mut().insert(TxSlot(slot.clone()));
+
+        let response = next.run(req).await;
+
+        if let Some(tx) = slot.lock().await.take() {
+            let result = if response.status().is_success() {
+                tx.commit().await
+            } else {
+                tx.rollback().await
+            };
+            if let Err(error) = result {
+                error!(%error, "failed to finalize transaction");
+            }
+        }
+
+        response
+    }
+}
+
+// the crate-wide error type, so handlers and queries can use `?` instead of
+// `.unwrap()` and still give clients a structured JSON error body
+mod error {
+    use axum::{response::IntoResponse, Json};
+    use serde_json::json;
+
+    use super::StatusCode;
+
+    pub type Result<T> = std::result::Result<T, Error>;
+
+    #[derive(Debug, thiserror::Error)]
+    pub enum Error {
+        #[error("database error: {0}")]
+        Database(#[from] sqlx::Error),
+    }
+
+    impl Error {
+        fn status_code(&self) -> StatusCode {
+            match self {
+                Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            }
+        }
+    }
+
+    impl IntoResponse for Error {
+        fn into_response(self) -> axum::response::Response {
+            let status = self.status_code();
+            let body = Json(json!({ "status": "error", "message": self.to_string() }));
+            (status, body).into_response()
+        }
+    }
+}
+
+// gates mutating routes behind an optional API key
+mod auth {
+    use axum::{
+        async_trait,
+        extract::{FromRef, FromRequestParts},
+        http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    };
+
+    // pulled out of `ProgramState` via `FromRef` so the extractor doesn't need
+    // the whole state, the same way `tx::Tx` only needs the connection pool
+    #[derive(Clone, Debug)]
+    struct ApiKey(Option<String>);
+
+    impl FromRef<super::ProgramState> for ApiKey {
+        fn from_ref(state: &super::ProgramState) -> Self {
+            ApiKey(state.api_key.clone())
+        }
+    }
+
+    /// Extractor that requires a valid API key when one is configured via
+    /// `Args::api_key`. Accepts `Authorization: Bearer <key>` or `x-api-key:
+    /// <key>` and compares in constant time. A deployment with no configured
+    /// key leaves the route open.
+    pub struct RequireApiKey;
+
+    #[async_trait]
+    impl<S> FromRequestParts<S> for RequireApiKey
+    where
+        S: Send + Sync,
+        ApiKey: FromRef<S>,
+    {
+        type Rejection = (StatusCode, &'static str);
+
+        async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+            let ApiKey(configured) = ApiKey::from_ref(state);
+            let Some(configured) = configured else {
+                return Ok(RequireApiKey);
+            };
+
+            let provided = parts
+                .headers
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .or_else(|| {
+                    parts
+                        .headers
+                        .get("x-api-key")
+                        .and_then(|value| value.to_str().ok())
+                });
+
+            match provided {
+                Some(provided) if constant_time_eq(provided.as_bytes(), configured.as_bytes()) => {
+                    Ok(RequireApiKey)
+                }
+                _ => Err((StatusCode::UNAUTHORIZED, "missing or invalid API key")),
+            }
+        }
+    }
+
+    // avoids leaking how many leading bytes matched via a short-circuiting comparison
+    fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+        if a.len() != b.len() {
+            return false;
+        }
+        a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}